@@ -0,0 +1,1781 @@
+//! Expansion tests. Each one writes a fixture crate to a temporary directory and runs the
+//! derive over it in-process, comparing the generated tokens.
+
+use super::*;
+
+/// Every warning printed so far, by any test.
+pub(crate) static WARNINGS: std::sync::Mutex<Vec<String>> = std::sync::Mutex::new(Vec::new());
+
+/// Whether a warning containing `needle` was printed. Tests run in parallel, so the needle has
+/// to be specific to the test.
+fn warned(needle: &str) -> bool {
+    WARNINGS.lock().unwrap().iter().any(|w| w.contains(needle))
+}
+
+/// A crate written to its own temporary directory, removed again when dropped.
+struct Fixture {
+    dir: PathBuf,
+}
+
+impl Fixture {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!(
+            "bevy_discovery_test_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        Fixture { dir }
+    }
+
+    /// Writes `contents` to `path`, relative to the crate root, creating its directory.
+    fn write(&self, path: &str, contents: &str) -> &Self {
+        let path = self.dir.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+        self
+    }
+
+    /// Expands the derive on a plugin struct annotated with `attrs`. The cache is kept in
+    /// the fixture, so consecutive expansions reuse it like consecutive builds would.
+    fn expand(&self, attrs: &str) -> String {
+        self.expand_at("", attrs)
+    }
+
+    /// Like [`Fixture::expand`], for the crate in the directory `crate_dir` of the fixture.
+    fn expand_at(&self, crate_dir: &str, attrs: &str) -> String {
+        let input = format!(
+            "#[discovery_cache(\"target/discovery\")] {} struct Plugin;",
+            attrs
+        );
+        let ast = syn::parse_str::<DeriveInput>(&input).unwrap();
+        expand_plugin(ast, self.dir.join(crate_dir)).to_string()
+    }
+
+    /// Expands the derive from a fresh scan, and again from the cache it wrote, asserting
+    /// that both give the same tokens.
+    fn expand_twice(&self, attrs: &str) -> String {
+        self.clear_cache();
+        let fresh = self.expand(attrs);
+        assert_eq!(fresh, self.expand(attrs));
+        fresh
+    }
+
+    /// Removes `path`, relative to the crate root.
+    fn remove(&self, path: &str) {
+        std::fs::remove_file(self.dir.join(path)).unwrap();
+    }
+
+    /// The files with an entry in any cache of the fixture.
+    fn cached_files(&self) -> FxHashSet<PathBuf> {
+        std::fs::read_dir(self.dir.join("target/discovery"))
+            .unwrap()
+            .flat_map(|file| load_cache(&file.unwrap().path(), true).into_keys())
+            .collect()
+    }
+
+    /// Removes the cache, so the next expansion scans every file again.
+    fn clear_cache(&self) {
+        let _ = std::fs::remove_dir_all(self.dir.join("target/discovery"));
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// `tokens` without whitespace, for matching generated code.
+fn compact(tokens: &str) -> String {
+    tokens.split_whitespace().collect()
+}
+
+/// The start of the statement registering `system` in `compact` tokens, up to its path.
+fn registration<'a>(compact: &'a str, system: &str) -> &'a str {
+    let end = compact.find(system).unwrap();
+    let start = compact[..end].rfind(&[';', '{'][..]).unwrap() + 1;
+    &compact[start..end]
+}
+
+#[test]
+fn expands_identically_twice() {
+    let fixture = Fixture::new("twice");
+    fixture
+        .write(
+            "src/main.rs",
+            "mod a; mod b; #[system] fn root() {} #[system(stage::POST_UPDATE)] fn late() {}",
+        )
+        .write("src/a.rs", "pub mod inner; #[system] pub fn first() {}")
+        .write("src/a/inner.rs", "#[startup_system] pub fn deep() {}")
+        .write(
+            "src/b/mod.rs",
+            "#[system] pub fn second() {} #[system] pub fn third() {}",
+        );
+    let fresh = fixture.expand("");
+    let cached = fixture.expand("");
+    assert_eq!(fresh, cached);
+    fixture.clear_cache();
+    assert_eq!(fresh, fixture.expand(""));
+    // Source order within each file, and declaration order across files.
+    let order = [
+        "self::root",
+        "self::late",
+        "self::a::first",
+        "self::a::inner::deep",
+        "self::b::second",
+        "self::b::third",
+    ];
+    let compact = compact(&fresh);
+    let positions = order
+        .iter()
+        .map(|path| compact.find(path).unwrap())
+        .collect::<Vec<_>>();
+    assert!(positions.windows(2).all(|w| w[0] < w[1]));
+}
+
+#[test]
+fn raw_identifier_modules() {
+    let fixture = Fixture::new("raw");
+    fixture
+        .write(
+            "r#proj/src/main.rs",
+            "pub mod r#type; #[path = \"r#gen/made.rs\"] mod made;",
+        )
+        .write(
+            "r#proj/src/type.rs",
+            "pub mod r#move; #[system] pub fn in_type() {}",
+        )
+        .write("r#proj/src/type/move.rs", "#[system] pub fn in_move() {}")
+        .write(
+            "r#proj/src/r#gen/made.rs",
+            "#[system] pub fn generated() {}",
+        );
+    let expanded = compact(&fixture.expand_at("r#proj", "#[discovery_quiet]"));
+    assert!(!expanded.contains("compile_error"), "{}", expanded);
+    assert!(expanded.contains("self::r#type::in_type"));
+    assert!(expanded.contains("self::r#type::r#move::in_move"));
+    assert!(expanded.contains("self::made::generated"));
+}
+
+#[test]
+fn ignored_items() {
+    let fixture = Fixture::new("ignore");
+    fixture
+        .write(
+            "src/main.rs",
+            "#[system] fn kept() {}
+             #[discovery_ignore] #[system] fn ignored_fn() {}
+             #[discovery_ignore] mod ignored_inline { #[system] pub fn inline_fn() {} }
+             #[discovery_ignore] mod skipped;",
+        )
+        // Never opened, so its contents don't have to parse.
+        .write("src/skipped.rs", "this isn't Rust");
+    let expanded = compact(&fixture.expand(""));
+    assert!(!expanded.contains("compile_error"), "{}", expanded);
+    assert!(expanded.contains("self::kept"));
+    assert!(!expanded.contains("ignored_fn"));
+    assert!(!expanded.contains("inline_fn"));
+    assert!(!expanded.contains("skipped"));
+}
+
+#[test]
+fn path_cycle() {
+    let fixture = Fixture::new("cycle");
+    fixture
+        .write("src/main.rs", "mod a; #[system] fn root() {}")
+        .write(
+            "src/a.rs",
+            "#[path = \"b.rs\"] mod b; #[system] pub fn in_a() {}",
+        )
+        .write(
+            "src/b.rs",
+            "#[path = \"a.rs\"] mod a; #[system] pub fn in_b() {}",
+        );
+    let expanded = compact(&fixture.expand(""));
+    assert!(expanded.contains("self::a::in_a"));
+    assert!(expanded.contains("self::a::b::in_b"));
+    let cycle = format!("skipping module cycle {}", fixture.dir.display());
+    assert!(warned(&cycle));
+}
+
+#[test]
+fn shared_module() {
+    let fixture = Fixture::new("shared");
+    fixture
+        .write("src/main.rs", "mod a; mod b;")
+        .write("src/a.rs", "#[path = \"common.rs\"] mod common;")
+        .write("src/b.rs", "#[path = \"common.rs\"] mod common;")
+        .write("src/common.rs", "#[system] pub fn once() {}");
+    let fresh = compact(&fixture.expand(""));
+    assert_eq!(fresh.matches("::once").count(), 1, "{}", fresh);
+    assert_eq!(fresh, compact(&fixture.expand("")));
+}
+
+#[test]
+fn deleted_module() {
+    let fixture = Fixture::new("deleted");
+    fixture
+        .write("src/main.rs", "mod gone; mod kept;")
+        .write("src/gone.rs", "#[system] pub fn stale() {}")
+        .write("src/kept.rs", "#[system] pub fn fresh() {}");
+    assert!(compact(&fixture.expand("")).contains("self::gone::stale"));
+    let gone = normalize_path(&fixture.dir.join("src/gone.rs"));
+    assert!(fixture.cached_files().contains(&gone));
+
+    // The file is deleted but still declared: an error, not the cached systems.
+    fixture.remove("src/gone.rs");
+    let expanded = compact(&fixture.expand(""));
+    assert!(expanded.contains("compile_error"));
+    assert!(!expanded.contains("stale"));
+
+    // With the declaration removed too, the build is clean and the entry is gone.
+    fixture.write("src/main.rs", "mod kept;");
+    let expanded = compact(&fixture.expand(""));
+    assert!(!expanded.contains("compile_error"), "{}", expanded);
+    assert!(expanded.contains("self::kept::fresh"));
+    assert!(!fixture.cached_files().contains(&gone));
+}
+
+#[test]
+fn string_ordering_labels() {
+    let fixture = Fixture::new("labels");
+    fixture
+        .write(
+            "src/main.rs",
+            "mod physics;
+         #[system(after = \"physics\", before = physics::step)] fn input() {}",
+        )
+        .write("src/physics.rs", "#[system] pub fn step() {}");
+    let expanded = compact(&fixture.expand(""));
+    assert!(!expanded.contains("compile_error"), "{}", expanded);
+    assert!(expanded.contains(".after(\"physics\")"));
+    assert!(expanded.contains(".before(physics::step)"));
+}
+
+#[test]
+fn ordering_by_name() {
+    let fixture = Fixture::new("names");
+    fixture
+        .write(
+            "src/main.rs",
+            "mod physics; use physics::helper;
+             #[system(after = step, before = helper, after = stepp)] fn input() {}",
+        )
+        .write(
+            "src/physics.rs",
+            "pub fn helper() {} #[system] pub fn step() {}",
+        );
+    let expanded = compact(&fixture.expand(""));
+    assert!(!expanded.contains("compile_error"), "{}", expanded);
+    assert!(expanded.contains(".after(self::physics::step)"));
+    assert!(expanded.contains(".before(helper)"));
+    assert!(expanded.contains(".after(stepp)"));
+    assert!(warned("no system named `stepp`"));
+}
+
+#[test]
+fn file_modules_in_inline_modules() {
+    let fixture = Fixture::new("inline");
+    fixture
+        .write("src/main.rs", "mod a; mod bar { pub mod foo; }")
+        .write("src/bar/foo.rs", "#[system] pub fn in_foo() {}")
+        .write("src/a.rs", "mod nested { pub mod leaf; }")
+        .write("src/a/nested/leaf.rs", "#[system] pub fn in_leaf() {}");
+    let expanded = compact(&fixture.expand(""));
+    assert!(!expanded.contains("compile_error"), "{}", expanded);
+    assert!(expanded.contains("self::bar::foo::in_foo"));
+    assert!(expanded.contains("self::a::nested::leaf::in_leaf"));
+}
+
+#[test]
+fn normalized_paths() {
+    let normalize = |path: &str| normalize_path(Path::new(path));
+    assert_eq!(
+        normalize("/crate/src/./a/../b.rs"),
+        Path::new("/crate/src/b.rs")
+    );
+    assert_eq!(normalize("/../src/b.rs"), Path::new("/src/b.rs"));
+    assert_eq!(normalize("../src/b.rs"), Path::new("../src/b.rs"));
+    assert_eq!(
+        normalize("/crate/r#src/r#b.rs"),
+        Path::new("/crate/r#src/r#b.rs")
+    );
+}
+
+#[cfg(windows)]
+#[test]
+fn normalized_windows_paths() {
+    let joined = Path::new(r"C:\crate\src").join("a/../b/c.rs");
+    assert_eq!(normalize_path(&joined), Path::new(r"C:\crate\src\b\c.rs"));
+    assert_eq!(
+        normalize_path(Path::new(r"C:\..\src")),
+        Path::new(r"C:\src")
+    );
+}
+
+#[test]
+fn dotted_module_paths() {
+    let fixture = Fixture::new("dotted");
+    fixture
+        .write(
+            "src/main.rs",
+            "mod a; #[path = \"./x/../b.rs\"] mod b; #[system] fn root() {}",
+        )
+        .write("src/a.rs", "#[path = \"../src/b.rs\"] mod b;")
+        .write("src/b.rs", "#[system] pub fn in_b() {}");
+    let expanded = compact(&fixture.expand(""));
+    // Both declarations are the same file, so its systems are registered once.
+    assert_eq!(expanded.matches("::in_b").count(), 1, "{}", expanded);
+    let b = fixture.dir.join("src/b.rs");
+    assert!(fixture.cached_files().contains(&b));
+}
+
+#[cfg(not(feature = "strict_systems"))]
+#[test]
+fn strict_plugin() {
+    let fixture = Fixture::new("strict");
+    fixture.write(
+        "src/main.rs",
+        "#[system] fn good(query: Query<&Transform>) {}
+         #[system] fn bad(count: u32, name: &str) {}
+         #[system(generics = <Transform>)] fn generic<T: Component>(query: Query<&T>) {}",
+    );
+    let lenient = compact(&fixture.expand(""));
+    assert!(!lenient.contains("compile_error"), "{}", lenient);
+    assert!(lenient.contains("self::bad"));
+    let strict = fixture.expand("#[discovery_strict]");
+    // Each error names where the function is written.
+    let bad = format!(
+        "self::bad ({}:2:23)",
+        fixture.dir.join("src/main.rs").display()
+    );
+    assert!(strict.contains(&format!("{} can't be a system: plain values", bad)));
+    assert!(strict.contains(&format!("{} can't be a system: references", bad)));
+    assert!(!strict.contains("self::good ("));
+    assert!(!strict.contains("self::generic ("));
+}
+
+#[test]
+fn ambiguous_module_files() {
+    let fixture = Fixture::new("ambiguous");
+    fixture
+        .write(
+            "src/main.rs",
+            "mod plain; #[cfg(feature = \"gated\")] mod gated;",
+        )
+        .write("src/plain.rs", "")
+        .write("src/plain/mod.rs", "")
+        .write("src/gated.rs", "")
+        .write("src/gated/mod.rs", "");
+    let expanded = fixture.expand("");
+    assert!(expanded.contains("file for module `plain` found at both"));
+    assert!(expanded.contains("file for module `gated` found at both"));
+}
+
+#[cfg(feature = "bevy_0_12")]
+#[test]
+fn gated_chains() {
+    let fixture = Fixture::new("chains");
+    fixture.write(
+        "src/main.rs",
+        "#[cfg(feature = \"a\")] #[system(chain)] fn first() {}
+         #[cfg(unix)] #[cfg(feature = \"b\")] #[system(chain)] fn second() {}
+         mod open {
+             #[system(chain)] pub fn third() {}
+             #[cfg(feature = \"c\")] #[system(chain)] pub fn fourth() {}
+         }",
+    );
+    let expanded = compact(&fixture.expand(""));
+    // With every member gated, so is the chain, which would otherwise be `().chain()`.
+    let gated = "#[cfg(any(all(feature=\"a\"),all(unix,feature=\"b\")))]app.add_systems(";
+    assert!(expanded.contains(gated), "{}", expanded);
+    assert!(expanded.contains(";app.add_systems(Update,(self::open::third,"));
+}
+
+#[test]
+fn gated_modules() {
+    let fixture = Fixture::new("gated");
+    fixture
+        .write(
+            "src/main.rs",
+            "#[cfg(feature = \"outer\")] mod outer {
+                 #[system] pub fn in_outer() {}
+                 #[cfg(unix)] pub mod inner { #[system] pub fn in_inner() {} }
+             }
+             #[cfg(feature = \"file\")] mod file;",
+        )
+        .write("src/file.rs", "mod plain { #[system] pub fn in_file() {} }");
+    let expanded = compact(&fixture.expand(""));
+    // The `add_system` prefix is shared by the registrations of every API.
+    for (cfg, system) in [
+        ("#[cfg(feature=\"outer\")]", "self::outer::in_outer"),
+        (
+            "#[cfg(feature=\"outer\")]#[cfg(unix)]",
+            "self::outer::inner::in_inner",
+        ),
+        ("#[cfg(feature=\"file\")]", "self::file::plain::in_file"),
+    ] {
+        let prefix = registration(&expanded, system);
+        assert!(
+            prefix.starts_with(&format!("{}app.add_system", cfg)),
+            "{}",
+            prefix
+        );
+    }
+}
+
+#[test]
+fn exotic_expressions_round_trip() {
+    let fixture = Fixture::new("exotic");
+    fixture.write(
+        "src/main.rs",
+        "#[system(stage = <Stages as StageSet>::BAZ, label = r#type::Label::<u8>::new())]
+         fn qualified() {}
+         #[system(after = <T as Tr<'a>>::f, before = crate::r#mod::f::<{ N + 1 }>)]
+         fn ordered() {}",
+    );
+    let expanded = compact(&fixture.expand_twice(""));
+    assert!(!expanded.contains("compile_error"), "{}", expanded);
+    assert!(expanded.contains("<StagesasStageSet>::BAZ"));
+    assert!(expanded.contains("r#type::Label::<u8>::new()"));
+    assert!(expanded.contains(".after(<TasTr<'a>>::f)"));
+    assert!(expanded.contains(".before(crate::r#mod::f::<{N+1}>)"));
+}
+
+#[test]
+fn orphaned_files() {
+    let fixture = Fixture::new("orphans");
+    fixture
+        .write("src/main.rs", "mod reached; #[system] fn root() {}")
+        .write("src/reached.rs", "#[system] pub fn reached() {}")
+        .write("src/orphan_plain.rs", "#[system] pub fn lost() {}")
+        .write(
+            "src/orphan_nested.rs",
+            "mod inner { #[cfg_attr(unix, system(stage::POST_UPDATE))] fn lost() {} }",
+        )
+        .write(
+            "src/not_orphan.rs",
+            "// #[system] in a comment
+             #[systems_helper] fn helper() { let _ = \"#[system]\"; }",
+        );
+    fixture.expand("#[discovery_warn_orphans]");
+    let orphan = |name: &str| {
+        let file = fixture.dir.join("src").join(name);
+        warned(&format!("{} contains systems", file.display()))
+    };
+    assert!(orphan("orphan_plain.rs"));
+    assert!(orphan("orphan_nested.rs"));
+    assert!(!orphan("not_orphan.rs"));
+    assert!(!orphan("reached.rs"));
+}
+
+#[test]
+fn cached_registrations_match_fresh_ones() {
+    let fixture = Fixture::new("styles");
+    fixture
+        .write("src/main.rs", "mod a; #[system] fn root() {}")
+        .write("src/a.rs", "#[startup_system] pub fn setup() {}");
+    let wrapped = compact(&fixture.expand_twice(""));
+    let bare = compact(&fixture.expand_twice("#[discovery_bare_systems]"));
+    // Entries cached for one style replay in the other.
+    fixture.expand("");
+    assert_eq!(bare, compact(&fixture.expand("#[discovery_bare_systems]")));
+    if cfg!(feature = "bevy_0_12") {
+        assert_eq!(wrapped, bare);
+    } else {
+        assert!(wrapped.contains("app.add_system(self::root.system());"));
+        assert!(wrapped.contains("app.add_startup_system(self::a::setup.system());"));
+        assert!(bare.contains("app.add_system(self::root);"));
+        assert!(bare.contains("app.add_startup_system(self::a::setup);"));
+    }
+}
+
+#[test]
+fn run_if_expressions() {
+    let fixture = Fixture::new("conditions");
+    fixture.write(
+        "src/main.rs",
+        "#[system(run_if = || true)] fn always() {}
+         #[system(run_if = |time: Res<Time>, x: Local<(u8, u8)>| time.delta() > x.0.into(), label = L)]
+         fn closure() {}
+         #[system(run_if = resource_exists::<Score>(), stage = stage::POST_UPDATE)] fn call() {}",
+    );
+    let expanded = compact(&fixture.expand_twice(""));
+    assert!(!expanded.contains("compile_error"), "{}", expanded);
+    let method = if cfg!(feature = "bevy_0_12") {
+        ".run_if"
+    } else {
+        ".with_run_criteria"
+    };
+    for condition in [
+        "(||true)",
+        "(|time:Res<Time>,x:Local<(u8,u8)>|time.delta()>x.0.into())",
+        "(resource_exists::<Score>())",
+    ] {
+        let call = format!("{}{}", method, condition);
+        assert!(expanded.contains(&call), "{} in {}", call, expanded);
+    }
+}
+
+#[test]
+fn platform_modules() {
+    let fixture = Fixture::new("platform");
+    fixture.write(
+        "src/main.rs",
+        "#[cfg(unix)] mod platform { #[system] pub fn input() {} }
+         #[cfg(windows)] mod platform;",
+    );
+    // Only the variant of the current target exists.
+    let unix = compact(&fixture.expand("#[discovery_deny_duplicates]"));
+    assert!(!unix.contains("compile_error"), "{}", unix);
+    assert_eq!(unix.matches("self::platform::input").count(), 1);
+
+    fixture.write("src/platform.rs", "#[system] pub fn input() {}");
+    let both = compact(&fixture.expand("#[discovery_deny_duplicates]"));
+    assert!(!both.contains("compile_error"), "{}", both);
+    assert!(both.contains("#[cfg(unix)]app.add_system"));
+    assert!(both.contains("#[cfg(windows)]app.add_system"));
+}
+
+#[test]
+fn edited_leaf_module() {
+    let fixture = Fixture::new("leaf");
+    fixture
+        .write("src/main.rs", "mod a; mod b; #[system] fn root() {}")
+        .write("src/a.rs", "mod leaf; #[system] pub fn in_a() {}")
+        .write("src/a/leaf.rs", "#[system] pub fn old() {}")
+        .write("src/b.rs", "#[system] pub fn in_b() {}");
+    fixture.expand("");
+    fixture.write(
+        "src/a/leaf.rs",
+        "#[system] pub fn old() {} #[system] pub fn added() {}",
+    );
+    let incremental = fixture.expand("");
+    assert!(compact(&incremental).contains("self::a::leaf::added"));
+    assert_eq!(incremental, fixture.expand_twice(""));
+
+    // Moving a module file rescans the unchanged file declaring it.
+    fixture.remove("src/b.rs");
+    fixture.write("src/b/mod.rs", "#[system] pub fn moved() {}");
+    let moved = fixture.expand("");
+    assert!(!moved.contains("compile_error"), "{}", moved);
+    assert!(compact(&moved).contains("self::b::moved"));
+    assert_eq!(moved, fixture.expand_twice(""));
+}
+
+#[test]
+fn tracked_files() {
+    let fixture = Fixture::new("tracked");
+    fixture
+        .write("src/main.rs", "mod a;")
+        .write("src/a.rs", "")
+        .write("src/unreached.rs", "fn helper() {}");
+    let tracks = |expanded: &str, name: &str| {
+        let file = fixture.dir.join("src").join(name);
+        compact(expanded).contains(&format!("include_bytes!(\"{}\")", file.display()))
+    };
+    let plain = fixture.expand("");
+    assert!(tracks(&plain, "main.rs") && tracks(&plain, "a.rs"));
+    assert!(!tracks(&plain, "unreached.rs"));
+    // Adding a system to a file the orphan check looked at rebuilds to warn about it.
+    let orphans = fixture.expand("#[discovery_warn_orphans]");
+    assert!(tracks(&orphans, "unreached.rs"));
+}
+
+#[test]
+fn deep_module_tree() {
+    const DEPTH: usize = 500;
+    let fixture = Fixture::new("deep");
+    fixture.write("src/main.rs", "mod m0;");
+    let mut dir = String::from("src");
+    for level in 0..DEPTH {
+        let contents = if level + 1 < DEPTH {
+            format!("pub mod m{}; #[system] pub fn s{}() {{}}", level + 1, level)
+        } else {
+            format!("#[system] pub fn s{}() {{}}", level)
+        };
+        fixture.write(&format!("{}/m{}.rs", dir, level), &contents);
+        dir = format!("{}/m{}", dir, level);
+    }
+    // Run on a thread with a small stack, so recursing per level would overflow it.
+    let expanded = std::thread::Builder::new()
+        .stack_size(256 * 1024)
+        .spawn(move || fixture.expand(""))
+        .unwrap()
+        .join()
+        .unwrap();
+    let expanded = compact(&expanded);
+    assert!(!expanded.contains("compile_error"));
+    let deepest = (0..DEPTH)
+        .map(|level| format!("m{}", level))
+        .collect::<Vec<_>>();
+    assert!(expanded.contains(&format!("self::{}::s{}", deepest.join("::"), DEPTH - 1)));
+}
+
+#[test]
+fn feature_roots() {
+    let fixture = Fixture::new("roots");
+    fixture
+        .write("src/main.rs", "#[system] fn in_game() {}")
+        .write("src/server.rs", "#[system] fn in_server() {}")
+        .write("src/editor.rs", "#[system] fn in_editor() {}");
+    let expanded = fixture.expand(
+        "#[root(\"src/main.rs\")]
+         #[root(feature = \"server\", path = \"src/server.rs\")]
+         #[root(feature = \"editor\", path = \"src/editor.rs\")]",
+    );
+    let registered = |cfg: &str, system: &str| {
+        registration(&compact(&expanded), system).starts_with(&format!("{}app.add_system", cfg))
+    };
+    assert!(registered(
+        "#[cfg(not(any(feature=\"editor\",feature=\"server\")))]",
+        "self::in_game",
+    ));
+    assert!(registered("#[cfg(feature=\"server\")]", "self::in_server"));
+    assert!(registered("#[cfg(feature=\"editor\")]", "self::in_editor"));
+    // The message as a string literal token, with its quotes escaped.
+    let conflict = r#""the roots for feature = \"editor\" and feature = \"server\" can't"#;
+    assert!(expanded.contains(conflict), "{}", expanded);
+    let gated = "#[cfg(feature=\"editor\")]#[cfg(feature=\"server\")]compile_error!";
+    assert!(compact(&expanded).contains(gated));
+    assert_eq!(expanded.matches("compile_error").count(), 1);
+}
+
+#[test]
+fn chunked_registrations() {
+    let fixture = Fixture::new("chunks");
+    let systems = (0..CHUNK_SIZE * 2 + 1)
+        .map(|i| format!("#[system] fn s{}() {{}}", i))
+        .collect::<String>();
+    fixture.write("src/main.rs", &systems);
+    let expanded = compact(&fixture.expand(""));
+    // `build` calls each chunk in order, and each chunk registers the next systems.
+    let calls = (0..3)
+        .map(|i| format!("Self::__discovery_register_chunk_{}(app);", i))
+        .collect::<String>();
+    assert!(expanded.contains(&format!("app:&mutApp){{{}}}", calls)));
+    assert!(!expanded.contains("__discovery_register_chunk_3"));
+    for (chunk, first, last) in [(0, 0, 255), (1, 256, 511), (2, 512, 512)] {
+        let body = format!("fn__discovery_register_chunk_{}(app:&mutApp){{", chunk);
+        let body = &expanded[expanded.find(&body).unwrap() + body.len()..];
+        let body = &body[..body.find('}').unwrap()];
+        assert_eq!(body.matches("app.add_system").count(), last - first + 1);
+        let registers = |i| {
+            [".", ")"]
+                .iter()
+                .any(|end| body.contains(&format!("self::s{}{}", i, end)))
+        };
+        assert!(registers(first) && registers(last));
+    }
+}
+
+#[test]
+fn shared_guard() {
+    let fixture = Fixture::new("guard");
+    fixture.write("src/main.rs", "#[system] fn root() {}");
+    let expanded = compact(&fixture.expand(
+        "#[discovery_guard] #[discovery_mode(plugin, method)] #[discovery_target(Setup::setup)]",
+    ));
+    assert_eq!(expanded.matches("structDiscoveryGuard").count(), 0);
+    assert_eq!(expanded.matches("struct__DiscoveryGuardPlugin;").count(), 1);
+    // Checked by both the trait method and `register`.
+    let check = "ifapp.world.contains_resource::<__DiscoveryGuardPlugin>()";
+    assert_eq!(expanded.matches(check).count(), 2, "{}", expanded);
+    // `AppBuilder` only hands out its world through a method.
+    let expanded = compact(
+        &fixture.expand("#[discovery_guard] #[discovery_method(app = bevy::app::AppBuilder)]"),
+    );
+    assert!(expanded.contains("build(&self,app:&mutbevy::app::AppBuilder)"));
+    let check = "ifapp.world().contains_resource::<__DiscoveryGuardPlugin>()";
+    assert!(expanded.contains(check), "{}", expanded);
+}
+
+#[test]
+fn system_arguments() {
+    let args = syn::parse_str::<SystemArgs>(
+        "my_stage!(a, b), label = Foo, after = helper, run_if = |a: u8, b: u8| a < b, label = Bar",
+    )
+    .unwrap();
+    assert_eq!(
+        token_string(args.stage.as_ref().unwrap()),
+        "my_stage ! (a , b)"
+    );
+    let labels = args.labels.iter().map(token_string).collect::<Vec<_>>();
+    assert_eq!(labels, ["Foo", "Bar"]);
+    assert_eq!(token_string(&args.after[0]), "helper");
+    assert_eq!(
+        token_string(args.run_if.as_ref().unwrap()),
+        "| a : u8 , b : u8 | a < b"
+    );
+    let args = syn::parse_str::<SystemArgs>("stage = stage::POST_UPDATE, generics = <A, B<C, D>>")
+        .unwrap();
+    assert_eq!(
+        token_string(args.stage.as_ref().unwrap()),
+        "stage :: POST_UPDATE"
+    );
+    assert_eq!(
+        token_string(args.generics.as_ref().unwrap()),
+        "< A , B < C , D > >"
+    );
+    for invalid in [
+        "stage::A, stage = stage::B",
+        "label = Foo bar",
+        "unknown = 1",
+    ] {
+        assert!(
+            syn::parse_str::<SystemArgs>(invalid).is_err(),
+            "{}",
+            invalid
+        );
+    }
+}
+
+#[cfg(not(feature = "bevy_0_12"))]
+#[test]
+fn positional_stage_with_keyed_arguments() {
+    let fixture = Fixture::new("positional");
+    fixture.write(
+        "src/main.rs",
+        "#[system(my_stage!(a, b), label = Foo)] fn staged() {}",
+    );
+    let expanded = compact(&fixture.expand(""));
+    let registration = "app.add_system_to_stage(my_stage!(a,b),self::staged.system().label(Foo));";
+    assert!(expanded.contains(registration), "{}", expanded);
+}
+
+#[test]
+fn uncached_expansion() {
+    let fixture = Fixture::new("uncached");
+    fixture
+        .write("src/main.rs", "mod a; #[system] fn root() {}")
+        .write("src/a.rs", "#[system(after = root)] pub fn first() {}");
+    let cached = fixture.expand_twice("");
+    fixture.clear_cache();
+    assert_eq!(cached, fixture.expand("#[discovery_no_cache]"));
+    assert!(!fixture.dir.join("target/discovery").exists());
+}
+
+#[test]
+fn inner_cfg_files() {
+    let fixture = Fixture::new("inner_cfg");
+    fixture
+        .write(
+            "src/main.rs",
+            "#[cfg(unix)] mod gated; mod plain; #[system] fn root() {}",
+        )
+        .write(
+            "src/gated.rs",
+            "#![cfg(feature = \"file\")] #[system] pub fn in_gated() {}",
+        )
+        .write(
+            "src/plain.rs",
+            "#![cfg(feature = \"file\")] #![allow(dead_code)] #[system] pub fn in_plain() {}",
+        );
+    let expanded = compact(&fixture.expand_twice(""));
+    for (cfg, system) in [
+        (
+            "#[cfg(unix)]#[cfg(feature=\"file\")]",
+            "self::gated::in_gated",
+        ),
+        ("#[cfg(feature=\"file\")]", "self::plain::in_plain"),
+        ("", "self::root"),
+    ] {
+        let prefix = registration(&expanded, system);
+        assert!(
+            prefix.starts_with(&format!("{}app.add_system", cfg)),
+            "{}",
+            prefix
+        );
+    }
+}
+
+#[test]
+fn whitespace_edits_keep_the_cached_entry() {
+    let fixture = Fixture::new("whitespace");
+    fixture
+        .write("src/main.rs", "mod a; #[system] fn root() {}")
+        .write("src/a.rs", "#[system] pub fn first() {}");
+    let entry = || {
+        std::fs::read_dir(fixture.dir.join("target/discovery"))
+            .unwrap()
+            .flat_map(|file| load_cache(&file.unwrap().path(), true))
+            .find(|(path, _)| path.ends_with("src/a.rs"))
+            .unwrap()
+            .1
+    };
+    let expanded = fixture.expand_twice("");
+    let before = entry();
+    fixture.write("src/a.rs", "// A comment.\n#[system]\npub fn first() { }\n");
+    assert_eq!(expanded, fixture.expand(""));
+    let after = entry();
+    assert_ne!(before.source_hash, after.source_hash);
+    assert_eq!(before.hash, after.hash);
+}
+
+#[test]
+fn fixed_argument_spans() {
+    // `hz` is only valid on fixed systems, so the error points at it.
+    let error = syn::parse_str::<SystemArgs>("label = Foo, hz = 60")
+        .err()
+        .unwrap();
+    assert_eq!(error.span().start().column, 13);
+    if cfg!(not(feature = "bevy_0_12")) {
+        // Without the schedule-based API, the flag needs a rate.
+        let error = syn::parse_str::<SystemArgs>("label = Foo, fixed")
+            .err()
+            .unwrap();
+        assert_eq!(error.span().start().column, 13);
+    }
+}
+
+#[cfg(feature = "bevy_0_12")]
+#[test]
+fn fixed_rates_per_cfg() {
+    let fixture = Fixture::new("fixed_rates");
+    fixture.write(
+        "src/main.rs",
+        "#[system(fixed, hz = 60)] fn steady() {}
+         #[cfg(feature = \"slow\")] #[system(fixed, hz = 30)] fn slow() {}
+         #[cfg(feature = \"slow\")] #[system(fixed, hz = 30)] fn slower() {}
+         #[cfg(unix)] mod native { #[system(fixed, hz = 60)] pub fn same() {} }",
+    );
+    let expanded = compact(&fixture.expand(""));
+    // Conflicting rates are only reported when both systems are enabled.
+    let error = "#[cfg(feature=\"slow\")]compile_error!(\"self::slow(src/main.rs:2:64)runsat30Hz,\
+                 butself::steady(src/main.rs:1:30)alreadysetthefixedtimestepto60Hz\");";
+    assert!(expanded.contains(error), "{}", expanded);
+    let error = "#[cfg(unix)]#[cfg(feature=\"slow\")]compile_error!(\"self::native::same(";
+    assert!(expanded.contains(error), "{}", expanded);
+    // `slower` conflicts with `steady` too, but nothing conflicts with `steady` on its own.
+    assert_eq!(
+        expanded.matches("compile_error!").count(),
+        3,
+        "{}",
+        expanded
+    );
+    for cfg in ["", "#[cfg(feature=\"slow\")]", "#[cfg(unix)]"] {
+        let insert = format!("{}ifapp.world.get_resource::<Time<Fixed>>()", cfg);
+        assert!(expanded.contains(&insert), "{}", expanded);
+    }
+
+    fixture.write(
+        "src/main.rs",
+        "#[system(fixed, hz = 60)] fn steady() {} #[system(fixed, hz = 30)] fn slow() {}",
+    );
+    let expanded = compact(&fixture.expand(""));
+    assert!(
+        expanded.contains("self::slow(src/main.rs:1:71)runsat30Hz"),
+        "{}",
+        expanded
+    );
+    assert!(!expanded.contains("]compile_error!"), "{}", expanded);
+
+    // The struct's cfg is repeated on every gated conflict.
+    fixture.write(
+        "src/main.rs",
+        "#[system(fixed, hz = 60)] fn steady() {}
+         #[cfg(feature = \"slow\")] #[system(fixed, hz = 30)] fn slow() {}
+         #[cfg(feature = \"fast\")] #[system(fixed, hz = 90)] fn fast() {}",
+    );
+    let expanded = compact(&fixture.expand("#[cfg(feature = \"plugin\")]"));
+    assert_eq!(
+        expanded.matches("compile_error!").count(),
+        3,
+        "{}",
+        expanded
+    );
+    assert_eq!(
+        expanded
+            .matches("#[cfg(feature=\"plugin\")]#[cfg(feature=")
+            .count(),
+        3,
+        "{}",
+        expanded
+    );
+}
+
+#[test]
+fn gated_plugin_conflicts() {
+    let fixture = Fixture::new("gated_conflicts");
+    fixture
+        .write("src/main.rs", "#[system] fn in_game() {}")
+        .write("src/server.rs", "#[system] fn in_server() {}")
+        .write("src/editor.rs", "#[system] fn in_editor() {}")
+        .write("src/tool.rs", "#[system] fn in_tool() {}");
+    let expanded = compact(&fixture.expand(
+        "#[cfg(feature = \"plugin\")]
+         #[root(\"src/main.rs\")]
+         #[root(feature = \"server\", path = \"src/server.rs\")]
+         #[root(feature = \"editor\", path = \"src/editor.rs\")]
+         #[root(feature = \"tool\", path = \"src/tool.rs\")]",
+    ));
+    // Every conflict goes away with the struct, not only the first one.
+    assert_eq!(
+        expanded.matches("compile_error!").count(),
+        3,
+        "{}",
+        expanded
+    );
+    assert_eq!(
+        expanded
+            .matches("#[cfg(feature=\"plugin\")]#[cfg(feature=")
+            .count(),
+        3,
+        "{}",
+        expanded
+    );
+    // Each conflict is its own item, gated on the struct and on both of its features.
+    for (a, b) in [("editor", "server"), ("editor", "tool"), ("server", "tool")] {
+        let item = format!(
+            "#[cfg(feature=\"plugin\")]#[cfg(feature=\"{}\")]#[cfg(feature=\"{}\")]compile_error!",
+            a, b
+        );
+        assert!(expanded.contains(&item), "{}", item);
+    }
+}
+
+#[test]
+fn system_locations() {
+    let fixture = Fixture::new("locations");
+    fixture
+        .write(
+            "src/main.rs",
+            "mod a;\nmod b;\n#[system(after = first)]\nfn ordered() {}\n",
+        )
+        .write(
+            "src/a.rs",
+            "#[system] pub fn first() {}\n\n#[system]\n#[system]\npub fn located_twice() {}\ninclude!(\"inc.rs\");\n",
+        )
+        .write("src/b.rs", "#[system] pub fn first() {}")
+        .write("src/inc.rs", "#[system] pub fn included() {}");
+    let expanded = fixture.expand("");
+    let ordered = "self::ordered (src/main.rs:4:4)";
+    assert!(expanded.contains(&format!("the ordering of {} is ambiguous", ordered)));
+    assert!(warned("self::a::located_twice (src/a.rs:5:8)"));
+
+    // The dry run lists the same locations, also for included files.
+    let dry_run = || {
+        let mut discovery = Discovery {
+            cache: FxHashMap::default(),
+            errors: Vec::new(),
+            config: Config::default(),
+        };
+        let main = fixture.dir.join("src/main.rs");
+        let reference = ModuleReference {
+            search_directory: root_search_directory(&main, &fixture.dir),
+            path: main.clone(),
+            module_path: quote! { self }.to_string(),
+            cfg: Vec::new(),
+        };
+        scan_tree(&mut discovery, vec![reference]);
+        dry_run_tree(&discovery.cache, &main, &fixture.dir)
+    };
+    let tree = dry_run();
+    assert!(
+        tree.contains("system self::a::first at src/a.rs:1:18"),
+        "{}",
+        tree
+    );
+    assert!(
+        tree.contains("system self::a::included at src/inc.rs:1:18"),
+        "{}",
+        tree
+    );
+
+    // Edits that keep the tokens keep the cached entry, with its systems moved.
+    fixture.write(
+        "src/a.rs",
+        "// Moved down.\n\n  #[system]  pub fn first() {}\n\n#[system]\n#[system]\npub fn located_twice() {}\ninclude!(\"inc.rs\");\n",
+    );
+    let entry = || {
+        std::fs::read_dir(fixture.dir.join("target/discovery"))
+            .unwrap()
+            .flat_map(|file| load_cache(&file.unwrap().path(), true))
+            .find(|(path, _)| path.ends_with("src/a.rs"))
+            .unwrap()
+            .1
+    };
+    let before = entry();
+    fixture.expand("");
+    let after = entry();
+    assert_eq!(before.hash, after.hash);
+    let first = &after.fn_paths[0];
+    assert_eq!((first.line, first.column), (3, 20));
+    assert!(warned("self::a::located_twice (src/a.rs:7:8)"));
+}
+
+/// Times scanning a 2000-line module with 400 systems, for the numbers in the crate docs. Run
+/// with `cargo test --release scan_timings -- --ignored --nocapture`.
+#[test]
+#[ignore]
+fn scan_timings() {
+    let fixture = Fixture::new("scan_timings");
+    let module = |padding: &str| {
+        (0..400)
+            .map(|i| {
+                format!(
+                    "#[system]\n{}fn s{}(query: Query<&Transform>) {{\n    let _ = query;\n}}\n\n",
+                    padding, i
+                )
+            })
+            .collect::<String>()
+    };
+    fixture
+        .write("src/main.rs", "mod big;")
+        .write("src/big.rs", &module(""));
+    let mut discovery = Discovery {
+        cache: FxHashMap::default(),
+        errors: Vec::new(),
+        config: Config::default(),
+    };
+    let main = fixture.dir.join("src/main.rs");
+    scan_tree(
+        &mut discovery,
+        vec![ModuleReference {
+            search_directory: root_search_directory(&main, &fixture.dir),
+            path: main.clone(),
+            module_path: quote! { self }.to_string(),
+            cfg: Vec::new(),
+        }],
+    );
+    let reference = discovery.cache[&main].referenced_files[0].clone();
+    let median = |cache: &FxHashMap<PathBuf, CacheEntry>| {
+        let mut runs = (0..7)
+            .map(|_| {
+                let start = std::time::Instant::now();
+                search_file_cache(cache, &discovery.config, &reference).unwrap();
+                start.elapsed()
+            })
+            .collect::<Vec<_>>();
+        runs.sort();
+        runs[3]
+    };
+    let untouched = median(&discovery.cache);
+    let rescan = median(&FxHashMap::default());
+    fixture.write("src/big.rs", &module("  "));
+    let whitespace = median(&discovery.cache);
+    println!(
+        "untouched: {:?}, whitespace edit: {:?}, rescan: {:?}",
+        untouched, whitespace, rescan
+    );
+}
+
+/// Times compiling the registrations of 4096 systems as one function and split into chunks,
+/// for the numbers in the crate docs. Bevy isn't available here, so the systems are registered
+/// on a stand-in `App` with the same method shape. Run with
+/// `cargo test --release chunk_compile_timings -- --ignored --nocapture`.
+#[test]
+#[ignore]
+fn chunk_compile_timings() {
+    let fixture = Fixture::new("chunk_timings");
+    let count = 4096;
+    let systems = (0..count)
+        .map(|i| format!("#[system] pub fn s{}() {{}}\n", i))
+        .collect::<String>();
+    fixture.write("src/main.rs", &systems);
+    let ast = syn::parse_str::<DeriveInput>("struct Systems;").unwrap();
+    let chunked = expand_plugin(ast, fixture.dir.clone()).to_string();
+    let single = format!(
+        "impl Plugin for Systems {{ fn build(&self, app: &mut App) {{ {} }} }}",
+        (0..count)
+            .map(|i| format!("app.add_system(self::s{}.system());", i))
+            .collect::<String>()
+    );
+    let prelude = "#![allow(dead_code)]
+        pub struct App;
+        pub trait Plugin { fn build(&self, app: &mut App); }
+        pub struct SystemBox(Box<dyn FnMut()>);
+        pub trait IntoSystem<Params> { fn system(self) -> SystemBox; }
+        impl<F: FnMut() + 'static> IntoSystem<()> for F {
+            fn system(self) -> SystemBox { SystemBox(Box::new(self)) }
+        }
+        impl App { pub fn add_system(&mut self, system: SystemBox) -> &mut Self { drop(system); self } }
+        pub struct Systems;";
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned());
+    for (name, plugin) in [("one function", &single), ("chunks", &chunked)] {
+        let path = fixture.dir.join("timed.rs");
+        // The attributes only mark the functions for the derive, and are left out here.
+        let functions = systems.replace("#[system] ", "");
+        std::fs::write(&path, format!("{}\n{}\n{}", prelude, functions, plugin)).unwrap();
+        for opt_level in ["0", "3"] {
+            let mut runs = (0..3)
+                .map(|_| {
+                    let start = std::time::Instant::now();
+                    let status = std::process::Command::new(&rustc)
+                        .args(["--edition", "2018", "--crate-type", "lib", "--emit", "obj"])
+                        .arg(format!("-Copt-level={}", opt_level))
+                        .arg("-o")
+                        .arg(fixture.dir.join("timed.o"))
+                        .arg(&path)
+                        .status()
+                        .unwrap();
+                    assert!(status.success());
+                    start.elapsed()
+                })
+                .collect::<Vec<_>>();
+            runs.sort();
+            println!("{}, opt-level {}: {:?}", name, opt_level, runs[1]);
+        }
+    }
+}
+
+#[cfg(feature = "bevy_0_12")]
+#[test]
+fn fixed_rate_app_types() {
+    let fixture = Fixture::new("fixed_app");
+    fixture.write("src/main.rs", "#[system(fixed, hz = 60)] fn steady() {}");
+    let expanded = compact(&fixture.expand(""));
+    assert!(expanded.contains("ifapp.world.get_resource::<Time<Fixed>>()"));
+    let expanded = compact(&fixture.expand("#[discovery_method(app = AppBuilder)]"));
+    assert!(expanded.contains("app:&mutAppBuilder"));
+    let check = "ifapp.world().get_resource::<Time<Fixed>>()";
+    assert!(expanded.contains(check), "{}", expanded);
+}
+
+#[test]
+fn systems_in_function_bodies() {
+    let fixture = Fixture::new("fn_bodies");
+    fixture.write(
+        "src/main.rs",
+        "#[system] fn outer() {
+             #[system] fn in_body_of_outer() {}
+             mod local { #[system] pub fn in_local_mod_of_outer() {} }
+         }
+         fn plain() { #[discovery_ignore] #[system] fn ignored_in_plain() {} }",
+    );
+    let expanded = compact(&fixture.expand_twice(""));
+    assert!(expanded.contains("self::outer"));
+    assert!(!expanded.contains("in_body_of_outer"), "{}", expanded);
+    assert!(!expanded.contains("in_local_mod_of_outer"), "{}", expanded);
+    // Each one is pointed out on every build, so also when expanding from the cache.
+    let warning = " is declared inside a function body and won't be discovered";
+    let needle = format!("self::outer::in_body_of_outer{}", warning);
+    let count = WARNINGS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|w| w.contains(&needle))
+        .count();
+    assert_eq!(count, 2);
+    assert!(warned(&format!(
+        "self::outer::local::in_local_mod_of_outer{}",
+        warning
+    )));
+    assert!(!warned("ignored_in_plain"));
+}
+
+#[test]
+fn module_system_sets() {
+    let fixture = Fixture::new("system_sets");
+    fixture.write(
+        "src/main.rs",
+        "#[system] fn outside() {}
+         #[discovery_set(label = PhysicsSet, run_if = physics_enabled)]
+         mod physics {
+             #[system] pub fn gravity() {}
+             #[system(stage = CoreStage::PostUpdate)] pub fn settle() {}
+             #[system] pub fn collide() {}
+         }",
+    );
+    let expanded = compact(&fixture.expand_twice(""));
+    if cfg!(feature = "bevy_0_12") {
+        let set = "app.add_systems(Update,(self::physics::gravity,self::physics::collide,)\
+                   .in_set(PhysicsSet).run_if(physics_enabled));\
+                   app.add_systems(PostUpdate,(self::physics::settle,)\
+                   .in_set(PhysicsSet).run_if(physics_enabled));";
+        assert!(expanded.contains(set), "{}", expanded);
+    } else {
+        // The set shares its label and run criteria, and members of another stage form a set
+        // of their own.
+        let head = "letset=SystemSet::new().label(PhysicsSet).with_run_criteria(physics_enabled);";
+        let sets = format!(
+            "{{{}letset=set.with_system(self::physics::gravity.system());\
+             letset=set.with_system(self::physics::collide.system());app.add_system_set(set);}}\
+             {{{}letset=set.with_system(self::physics::settle.system());\
+             app.add_system_set_to_stage(CoreStage::PostUpdate,set);}}",
+            head, head
+        );
+        assert!(expanded.contains(&sets), "{}", expanded);
+    }
+    assert!(registration(&expanded, "self::outside").starts_with("app.add_system"));
+}
+
+#[cfg(not(feature = "bevy_0_12"))]
+#[test]
+fn grouped_stages() {
+    let fixture = Fixture::new("grouped_stages");
+    fixture
+        .write(
+            "src/main.rs",
+            "mod more;
+             #[system] fn b_first() {}
+             #[system(stage = CoreStage::PostUpdate)] fn late_first() {}
+             #[system] fn a_second() {}",
+        )
+        .write(
+            "src/more.rs",
+            "#[system(stage = CoreStage::PostUpdate)] pub fn late_second() {}
+             #[system] pub fn third() {}",
+        );
+    let expanded = compact(&fixture.expand_twice("#[discovery_group_stages]"));
+    // One set per stage, at its first system, with the systems in discovery order.
+    let sets = "{letset=SystemSet::new();\
+                letset=set.with_system(self::b_first.system());\
+                letset=set.with_system(self::a_second.system());\
+                letset=set.with_system(self::more::third.system());\
+                app.add_system_set(set);}\
+                {letset=SystemSet::new();\
+                letset=set.with_system(self::late_first.system());\
+                letset=set.with_system(self::more::late_second.system());\
+                app.add_system_set_to_stage(CoreStage::PostUpdate,set);}";
+    assert!(expanded.contains(sets), "{}", expanded);
+    assert!(!expanded.contains("app.add_system("), "{}", expanded);
+}
+
+#[test]
+fn configured_systems() {
+    let fixture = Fixture::new("configured");
+    fixture
+        .write("src/main.rs", "mod a; #[system] fn root() {}")
+        .write("src/a.rs", "#[system(label = Own)] pub fn first() {}");
+    let attrs = "#[discovery_configure(|s| s.in_set(GameplaySet).after(setup))]";
+    let fresh = compact(&fixture.expand_twice(attrs));
+    // Reusing the cache for one file while rescanning the other appends the same calls.
+    fixture.write(
+        "src/main.rs",
+        "mod a; #[system] fn root() {} #[system] fn added() {}",
+    );
+    let mixed = compact(&fixture.expand(attrs));
+    let configured = if cfg!(feature = "bevy_0_12") {
+        [
+            "app.add_systems(Update,self::root.in_set(GameplaySet).after(setup));",
+            "app.add_systems(Update,self::a::first.label(Own).in_set(GameplaySet).after(setup));",
+        ]
+    } else {
+        [
+            "app.add_system(self::root.system().in_set(GameplaySet).after(setup));",
+            "app.add_system(self::a::first.system().label(Own).in_set(GameplaySet).after(setup));",
+        ]
+    };
+    for registration in configured {
+        assert!(fresh.contains(registration), "{}", fresh);
+        assert!(mixed.contains(registration), "{}", mixed);
+    }
+    assert!(mixed.contains("self::added"));
+}
+
+#[test]
+fn discovered_events() {
+    let fixture = Fixture::new("events");
+    fixture
+        .write("src/main.rs", "mod a; #[system] fn root() {}")
+        .write(
+            "src/a.rs",
+            "#[system] pub fn hit() {} #[discovery_event] pub struct Hit;
+            #[discovery_event] pub struct Generic<T>(T);",
+        );
+    let expanded = compact(&fixture.expand_twice(""));
+    let event = expanded
+        .find("app.add_event::<self::a::Hit>();")
+        .expect(&expanded);
+    assert!(
+        event < expanded.find("self::a::hit").unwrap(),
+        "{}",
+        expanded
+    );
+    assert!(!expanded.contains("Generic"), "{}", expanded);
+    let generic = check_type(
+        quote! { pub struct Generic<T>(T); },
+        "#[discovery_event]",
+        "events",
+    );
+    assert_eq!(
+        generic.unwrap_err().to_string(),
+        "generic events can't be registered automatically"
+    );
+    let function = check_type(quote! { fn hit() {} }, "#[discovery_event]", "events");
+    assert_eq!(
+        function.unwrap_err().to_string(),
+        "#[discovery_event] can only be applied to structs and enums"
+    );
+}
+
+#[test]
+fn discovered_resources() {
+    let fixture = Fixture::new("resources");
+    fixture
+        .write("src/main.rs", "mod a; #[system] fn root() {}")
+        .write(
+            "src/a.rs",
+            "#[system] pub fn score() {} #[discovery_event] pub struct Hit;
+            #[discovery_resource] #[derive(Default)] pub struct Counter(u32);
+            #[discovery_resource(insert = a::Score::new(3))] pub struct Score(u32);",
+        );
+    let expanded = compact(&fixture.expand_twice(""));
+    let init = expanded
+        .find("app.init_resource::<self::a::Counter>();")
+        .expect(&expanded);
+    let insert = expanded
+        .find("app.insert_resource(a::Score::new(3));")
+        .expect(&expanded);
+    let event = expanded.find("app.add_event::<self::a::Hit>();").unwrap();
+    let system = expanded.find("self::a::score").unwrap();
+    assert!(
+        event < init && init < insert && insert < system,
+        "{}",
+        expanded
+    );
+    assert!(
+        !expanded.contains("init_resource::<self::a::Score>"),
+        "{}",
+        expanded
+    );
+}
+
+#[test]
+fn discovered_plugins() {
+    let fixture = Fixture::new("plugins");
+    fixture
+        .write(
+            "src/main.rs",
+            "mod a; mod b; #[system] fn root() {} #[discovery_plugin_item] struct RootPlugin;",
+        )
+        .write(
+            "src/a.rs",
+            "#[discovery_event] pub struct Hit;
+            #[discovery_plugin_item(a::APlugin::new(3))] pub struct APlugin;",
+        )
+        .write("src/b.rs", "#[discovery_plugin_item] pub struct BPlugin;");
+    let add = if cfg!(feature = "bevy_0_12") {
+        "app.add_plugins("
+    } else {
+        "app.add_plugin("
+    };
+    let plugins = [
+        format!(
+            "{}<self::RootPluginas::core::default::Default>::default());",
+            add
+        ),
+        format!("{}a::APlugin::new(3));", add),
+        format!(
+            "{}<self::b::BPluginas::core::default::Default>::default());",
+            add
+        ),
+    ];
+    let fresh = compact(&fixture.expand_twice(""));
+    // Rescanning one file keeps the order of the plugins found in the others.
+    fixture.write(
+        "src/b.rs",
+        "#[discovery_plugin_item] pub struct BPlugin; // edited",
+    );
+    let mixed = compact(&fixture.expand(""));
+    for expanded in [fresh, mixed] {
+        let positions = plugins
+            .iter()
+            .map(|plugin| expanded.find(plugin.as_str()).expect(&expanded))
+            .collect::<Vec<_>>();
+        assert!(positions.windows(2).all(|w| w[0] < w[1]), "{}", expanded);
+        assert!(
+            positions[2] < expanded.find("app.add_event").unwrap(),
+            "{}",
+            expanded
+        );
+        assert!(
+            positions[2] < expanded.find("self::root").unwrap(),
+            "{}",
+            expanded
+        );
+    }
+}
+
+#[test]
+fn registration_order() {
+    let fixture = Fixture::new("order");
+    fixture
+        .write(
+            "src/main.rs",
+            "mod a;
+            #[system] fn update_b() {}
+            #[system(stage = CoreStage::PostUpdate, priority = 5)] fn late_z() {}
+            #[system(stage = CoreStage::PostUpdate)] fn late_m() {}
+            #[system(priority = -1)] fn early() {}",
+        )
+        .write(
+            "src/a.rs",
+            "#[system] pub fn a_update() {}
+            #[system(stage = CoreStage::PostUpdate)] pub fn late_a() {}",
+        );
+    let order = |expanded: &str, systems: &[&str]| {
+        let positions = systems
+            .iter()
+            .map(|system| expanded.find(&format!("self::{}", system)).expect(system))
+            .collect::<Vec<_>>();
+        assert!(
+            positions.windows(2).all(|w| w[0] < w[1]),
+            "{:?} in {}",
+            systems,
+            expanded
+        );
+    };
+    // Within each stage, by priority and then in source order.
+    let expanded = compact(&fixture.expand_twice(""));
+    order(&expanded, &["early", "update_b", "a::a_update"]);
+    order(&expanded, &["late_m", "a::late_a", "late_z"]);
+    // Or by path among systems of equal priority.
+    let expanded = compact(&fixture.expand_twice("#[discovery_sort(alpha)]"));
+    order(&expanded, &["early", "a::a_update", "update_b"]);
+    order(&expanded, &["a::late_a", "late_m", "late_z"]);
+}
+
+#[test]
+fn labels_named_like_systems() {
+    let fixture = Fixture::new("label_names");
+    fixture.write(
+        "src/main.rs",
+        "#[system(label = MovementLabel)] fn movement_label() {}
+        #[system(after = MovementLabel, after = movement_labl)] fn follow_camera() {}",
+    );
+    let expanded = compact(&fixture.expand(""));
+    assert!(expanded.contains(".after(MovementLabel)"), "{}", expanded);
+    assert!(!warned(
+        "`MovementLabel` was discovered for the ordering of self::follow_camera"
+    ));
+    assert!(warned(
+        "`movement_labl` was discovered for the ordering of self::follow_camera"
+    ));
+}
+
+#[test]
+fn custom_schedules() {
+    let args = syn::parse_str::<SystemArgs>("schedule = MySchedule");
+    if cfg!(not(feature = "bevy_0_12")) {
+        let error = args.err().unwrap().to_string();
+        assert_eq!(error, "`schedule` requires the `bevy_0_12` feature");
+        return;
+    }
+    assert_eq!(
+        token_string(args.unwrap().schedule.as_ref().unwrap()),
+        "MySchedule"
+    );
+    let error =
+        syn::parse_str::<SystemArgs>("stage = CoreStage::PostUpdate, schedule = MySchedule")
+            .err()
+            .unwrap();
+    assert_eq!(
+        error.to_string(),
+        "a system can't be given both a stage and a schedule"
+    );
+    let fixture = Fixture::new("schedules");
+    fixture
+        .write(
+            "src/main.rs",
+            "mod a; #[system(schedule = MySchedule)] fn own() {}",
+        )
+        .write(
+            "src/a.rs",
+            "#[system(schedule = my::Other)] pub fn other() {}",
+        );
+    let expanded = compact(&fixture.expand_twice(""));
+    assert!(
+        expanded.contains("app.add_systems(MySchedule,self::own);"),
+        "{}",
+        expanded
+    );
+    assert!(
+        expanded.contains("app.add_systems(my::Other,self::a::other);"),
+        "{}",
+        expanded
+    );
+}
+
+#[test]
+fn system_set_membership() {
+    let args = syn::parse_str::<SystemArgs>("in_set = MySet");
+    if cfg!(not(feature = "bevy_0_12")) {
+        let error = args.err().unwrap().to_string();
+        assert_eq!(
+            error,
+            "`in_set` requires the `bevy_0_12` feature, use `label` instead"
+        );
+        return;
+    }
+    let fixture = Fixture::new("in_sets");
+    fixture
+        .write("src/main.rs", "mod a; #[system(in_set = MySet)] fn own() {}")
+        .write(
+            "src/a.rs",
+            "#[system(schedule = MySchedule, in_set = MySet, in_set = sets::Other)] pub fn both() {}",
+        );
+    let expanded = compact(&fixture.expand_twice(""));
+    let registrations = [
+        "app.add_systems(Update,self::own.in_set(MySet));",
+        "app.add_systems(MySchedule,self::a::both.in_set(MySet).in_set(sets::Other));",
+    ];
+    for registration in registrations {
+        assert!(expanded.contains(registration), "{}", expanded);
+    }
+}
+
+#[test]
+fn exclusive_systems() {
+    let fixture = Fixture::new("exclusive");
+    fixture
+        .write(
+            "src/main.rs",
+            "mod a; #[exclusive_system] fn apply(world: &mut World) {}",
+        )
+        .write(
+            "src/a.rs",
+            "#[exclusive_system(stage = CoreStage::PostUpdate)]
+            pub fn late(world: &mut World) {}",
+        );
+    let expanded = compact(&fixture.expand_twice(""));
+    let registrations = if cfg!(feature = "bevy_0_12") {
+        [
+            "app.add_systems(Update,self::apply);",
+            "app.add_systems(PostUpdate,self::a::late);",
+        ]
+    } else {
+        [
+            "app.add_system(self::apply.exclusive_system());",
+            "app.add_system_to_stage(CoreStage::PostUpdate,self::a::late.exclusive_system());",
+        ]
+    };
+    for registration in registrations {
+        assert!(expanded.contains(registration), "{}", expanded);
+    }
+}
+
+#[test]
+fn generic_instantiations() {
+    let fixture = Fixture::new("generics");
+    fixture.write(
+        "src/main.rs",
+        "#[system(generics = <Player>)] #[system(generics = ::<Enemy, 2>)]
+        fn move_entity<T: Component, const N: usize>() {}",
+    );
+    let expanded = compact(&fixture.expand_twice(""));
+    let registrations = if cfg!(feature = "bevy_0_12") {
+        [
+            "app.add_systems(Update,self::move_entity::<Player>);",
+            "app.add_systems(Update,self::move_entity::<Enemy,2>);",
+        ]
+    } else {
+        [
+            "app.add_system(self::move_entity::<Player>.system());",
+            "app.add_system(self::move_entity::<Enemy,2>.system());",
+        ]
+    };
+    for registration in registrations {
+        assert!(expanded.contains(registration), "{}", expanded);
+    }
+}
+
+#[test]
+fn glob_discovery() {
+    let fixture = Fixture::new("glob");
+    fixture
+        .write("src/main.rs", "mod a; #[system] fn root() {}")
+        .write("src/a.rs", "#[system] pub fn reached() {}")
+        .write("src/generated/units.rs", "#[system] pub fn spawn() {}")
+        .write("src/generated/deep/mod.rs", "#[system] pub fn nested() {}")
+        .write("src/generated/notes.txt", "#[system] fn text() {}");
+    let glob = "#[discovery_glob(\"src/generated/**/*.rs\")]";
+    let globbed = compact(&fixture.expand_twice(glob));
+    assert!(
+        globbed.contains("self::generated::units::spawn"),
+        "{}",
+        globbed
+    );
+    assert!(
+        globbed.contains("self::generated::deep::nested"),
+        "{}",
+        globbed
+    );
+    assert!(!globbed.contains("text"), "{}", globbed);
+    // The globs replace the default root, unless it's given explicitly.
+    assert!(!globbed.contains("self::root"), "{}", globbed);
+    let both = compact(&fixture.expand_twice(&format!("#[root(\"src/main.rs\")] {}", glob)));
+    for system in [
+        "self::root",
+        "self::a::reached",
+        "self::generated::units::spawn",
+    ] {
+        assert!(both.contains(system), "{}", both);
+    }
+}
+
+#[test]
+fn excluded_directories() {
+    let fixture = Fixture::new("exclude");
+    fixture
+        .write(
+            "src/main.rs",
+            "mod a; mod generated; #[system] fn root() {}",
+        )
+        .write("src/a.rs", "#[system] pub fn reached() {}")
+        .write(
+            "src/generated/mod.rs",
+            "mod big; #[system] pub fn generated() {}",
+        )
+        .write("src/generated/big.rs", "#[system] pub fn big() {}");
+    let attrs = "#[discovery_exclude(\"src/generated\")]";
+    let expanded = compact(&fixture.expand_twice(attrs));
+    assert!(expanded.contains("self::a::reached"), "{}", expanded);
+    assert!(!expanded.contains("generated"), "{}", expanded);
+    // The declaring file's entry records the excluded file as skipped, without an entry of
+    // its own, so it isn't read again until `main.rs` changes.
+    let excluded = fixture.dir.join("src/generated/mod.rs");
+    let main = fixture.dir.join("src/main.rs");
+    let cached = fixture.cached_files();
+    assert!(cached.contains(&main) && !cached.contains(&excluded));
+    let entries = std::fs::read_dir(fixture.dir.join("target/discovery"))
+        .unwrap()
+        .flat_map(|file| load_cache(&file.unwrap().path(), true))
+        .collect::<Vec<_>>();
+    let (_, entry) = entries.iter().find(|(path, _)| *path == main).unwrap();
+    assert_eq!(entry.excluded_files, [excluded]);
+    fixture.write("src/generated/mod.rs", "#[system] pub fn edited() {}");
+    assert_eq!(compact(&fixture.expand(attrs)), expanded);
+}
+
+#[test]
+fn included_files() {
+    let fixture = Fixture::new("include");
+    fixture
+        .write(
+            "src/main.rs",
+            "mod generated { include!(\"generated/systems.rs\"); }
+            include!(concat!(env!(\"OUT_DIR\"), \"/built.rs\"));",
+        )
+        .write(
+            "src/generated/systems.rs",
+            "#[system] pub fn spawn() {} mod inner { #[system] pub fn deep() {} }",
+        );
+    let expanded = compact(&fixture.expand_twice(""));
+    assert!(expanded.contains("self::generated::spawn"), "{}", expanded);
+    assert!(
+        expanded.contains("self::generated::inner::deep"),
+        "{}",
+        expanded
+    );
+    let included = fixture.dir.join("src/generated/systems.rs");
+    let tracked = format!("include_bytes!({:?})", included.display().to_string());
+    assert!(expanded.contains(&tracked), "{}", expanded);
+    // The included file is tracked, so editing it alone is picked up.
+    fixture.write("src/generated/systems.rs", "#[system] pub fn replaced() {}");
+    let edited = compact(&fixture.expand(""));
+    assert!(edited.contains("self::generated::replaced"), "{}", edited);
+    assert!(!edited.contains("self::generated::spawn"), "{}", edited);
+    assert!(!fixture.cached_files().contains(&included));
+}
+
+#[test]
+fn library_roots() {
+    let fixture = Fixture::new("library");
+    fixture
+        .write("src/main.rs", "#[system] fn bin_only() {}")
+        .write(
+            "src/lib.rs",
+            "pub mod systems; #[system] pub fn lib_root() {}",
+        )
+        .write("src/systems.rs", "#[system] pub fn moved() {}");
+    let attrs = "#[root(\"src/main.rs\")] #[root(crate = \"my-game\", path = \"src/lib.rs\")]";
+    let expanded = compact(&fixture.expand_twice(attrs));
+    for system in [
+        "self::bin_only",
+        "::my_game::lib_root",
+        "::my_game::systems::moved",
+    ] {
+        assert!(expanded.contains(system), "{}", expanded);
+    }
+    assert!(!expanded.contains("self::systems"), "{}", expanded);
+    let invalid = fixture.expand("#[root(crate = \"my game\", path = \"src/lib.rs\")]");
+    assert!(invalid.contains("expected a crate name"), "{}", invalid);
+}
+
+#[test]
+fn integration_test_roots() {
+    let fixture = Fixture::new("integration");
+    fixture
+        .write("src/main.rs", "#[system] fn game() {}")
+        .write(
+            "tests/integration.rs",
+            "mod common; #[system] fn setup() {} #[cfg(test)] #[system] fn checked() {}",
+        )
+        .write(
+            "tests/common/mod.rs",
+            "mod spawn; #[system] pub fn shared() {}",
+        )
+        .write("tests/common/spawn.rs", "#[system] pub fn spawn() {}");
+    let attrs = "#[root(\"tests/integration.rs\")]";
+    let expanded = compact(&fixture.expand_twice(attrs));
+    for system in [
+        "self::setup",
+        "self::common::shared",
+        "self::common::spawn::spawn",
+    ] {
+        assert!(expanded.contains(system), "{}", expanded);
+    }
+    assert!(!expanded.contains("self::game"), "{}", expanded);
+    assert!(!expanded.contains("self::checked"), "{}", expanded);
+    let with_tests = compact(&fixture.expand(&format!("{} #[discovery_include_tests]", attrs)));
+    assert!(with_tests.contains("self::checked"), "{}", with_tests);
+    // A helper module written as a single file next to the root is found the same way.
+    fixture.remove("tests/common/mod.rs");
+    fixture.remove("tests/common/spawn.rs");
+    fixture.write("tests/common.rs", "#[system] pub fn flat() {}");
+    let flat = compact(&fixture.expand(attrs));
+    assert!(flat.contains("self::common::flat"), "{}", flat);
+}
+
+#[test]
+fn truncated_cache() {
+    let fixture = Fixture::new("truncated");
+    fixture
+        .write("src/main.rs", "mod a; mod b; #[system] fn root() {}")
+        .write("src/a.rs", "#[system(after = root)] pub fn first() {}")
+        .write(
+            "src/b.rs",
+            "#[system(stage = CoreStage::PostUpdate)] pub fn second() {}",
+        );
+    let fresh = fixture.expand_twice("");
+    let caches = std::fs::read_dir(fixture.dir.join("target/discovery"))
+        .unwrap()
+        .map(|file| file.unwrap().path())
+        .collect::<Vec<_>>();
+    for cache in caches.iter() {
+        let contents = std::fs::read(cache).unwrap();
+        std::fs::write(cache, &contents[..contents.len() / 2]).unwrap();
+    }
+    // Every file is scanned again instead of registering what was left of the cache.
+    assert_eq!(fixture.expand(""), fresh);
+    for cache in caches.iter() {
+        assert!(warned(&format!(
+            "discarding corrupt cache {}",
+            cache.display()
+        )));
+    }
+    assert_eq!(fixture.expand(""), fresh);
+    assert_eq!(fixture.cached_files().len(), 3);
+}
+
+#[test]
+fn source_encodings() {
+    let fixture = Fixture::new("encodings");
+    fixture
+        .write("src/main.rs", "mod bom; #[system] fn root() {}")
+        .write("src/bom.rs", "\u{feff}#[system] pub fn marked() {}");
+    let expanded = compact(&fixture.expand_twice(""));
+    assert!(expanded.contains("self::bom::marked"), "{}", expanded);
+    assert!(!expanded.contains("compile_error"), "{}", expanded);
+    fixture.write("src/main.rs", "mod bom; mod latin1; #[system] fn root() {}");
+    let latin1 = fixture.dir.join("src/latin1.rs");
+    std::fs::write(&latin1, b"// caf\xe9\n#[system] pub fn lost() {}").unwrap();
+    let expanded = fixture.expand("");
+    let message = format!("{} isn't valid UTF-8", latin1.display());
+    assert!(expanded.contains("compile_error"), "{}", expanded);
+    assert!(expanded.contains(&message), "{}", expanded);
+}